@@ -1,6 +1,8 @@
 use std::collections::HashMap;
 use std::env;
+use std::process::Stdio;
 use std::sync::Arc;
+use std::time::Instant;
 
 use async_trait::async_trait;
 use log::info;
@@ -8,7 +10,12 @@ use russh::keys::*;
 use russh::server::{Msg, Server as _, Session};
 use russh::*;
 use serde::{Deserialize, Serialize};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::process::{ChildStdin, Command};
 use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
 use unkey::models::VerifyKeyRequest;
 use unkey::Client as UnkeyClient;
 
@@ -18,6 +25,12 @@ lazy_static::lazy_static! {
     static ref UNKEY_API_ID: String = get_env("UNKEY_API_ID", "");
 }
 
+/// Banner sent to clients during authentication, explaining that a verified
+/// key whose Unkey rate limit is exhausted is refused until the limit resets.
+static RATE_LIMIT_BANNER: &str =
+    "Access is gated by Unkey. Keys whose rate limit is exhausted are refused \
+     until the limit resets; check your remaining quota and try again.\r\n";
+
 /// Helper function to retrieve environment variables with a default fallback value
 fn get_env(key: &str, default: &str) -> String {
     env::var(key).unwrap_or_else(|_| default.to_string())
@@ -27,8 +40,192 @@ fn get_env(key: &str, default: &str) -> String {
 #[derive(Serialize, Deserialize, Debug)]
 struct KeyVerifyData {
     valid: bool,
+    key_id: String,
+    /// Free-form metadata attached to the Unkey key (permissions, authorized
+    /// SSH fingerprints, …)
+    meta: Option<serde_json::Value>,
+    /// Rate-limit state reported by Unkey for this key, if configured
+    ratelimit: Option<RateLimitInfo>,
+}
+
+/// The subset of an Unkey rate-limit response this server acts on
+#[derive(Serialize, Deserialize, Debug)]
+struct RateLimitInfo {
+    limit: i64,
+    remaining: i64,
+    reset: i64,
+}
+
+impl KeyVerifyData {
+    /// Returns `true` when the key carries a rate limit with no budget left
+    fn rate_limited(&self) -> bool {
+        self.ratelimit
+            .as_ref()
+            .map(|r| r.remaining <= 0)
+            .unwrap_or(false)
+    }
+
+    /// Permission scopes granted to the key, drawn from the `permissions` and
+    /// `roles` metadata arrays and consulted by the feature gates
+    fn scopes(&self) -> Vec<String> {
+        let mut scopes = Vec::new();
+        if let Some(meta) = self.meta.as_ref() {
+            for field in ["permissions", "roles"] {
+                if let Some(items) = meta.get(field).and_then(|value| value.as_array()) {
+                    scopes.extend(
+                        items
+                            .iter()
+                            .filter_map(|item| item.as_str().map(|s| s.to_string())),
+                    );
+                }
+            }
+        }
+        scopes
+    }
+    /// The SSH public-key fingerprints the operator authorized through the
+    /// key's `authorized_fingerprints` metadata array
+    fn authorized_fingerprints(&self) -> Vec<String> {
+        self.meta
+            .as_ref()
+            .and_then(|meta| meta.get("authorized_fingerprints"))
+            .and_then(|value| value.as_array())
+            .map(|items| {
+                items
+                    .iter()
+                    .filter_map(|item| item.as_str().map(|s| s.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// Terminal parameters requested by a client through `pty_request`
+#[derive(Clone, Debug)]
+struct PtyRequest {
+    term: String,
+    col_width: u32,
+    row_height: u32,
+}
+
+/// Direction of a recorded chunk relative to the server
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+enum Stream {
+    Stdin,
+    Stdout,
+}
+
+/// A single timestamped chunk in a MessagePack recording stream
+#[derive(Serialize, Deserialize, Debug)]
+struct RecordItem {
+    time_offset_ms: u64,
+    stream: Stream,
+    data: Vec<u8>,
+}
+
+/// On-disk serialization format for a recording
+#[derive(Clone, Copy, Debug)]
+enum RecordFormat {
+    /// asciinema v2 cast file (newline-delimited JSON)
+    Asciinema,
+    /// Compact MessagePack stream of [`RecordItem`] values
+    MessagePack,
+}
+
+impl RecordFormat {
+    /// Selects the format from the `RECORD_FORMAT` environment variable,
+    /// defaulting to asciinema
+    fn from_env() -> Self {
+        match get_env("RECORD_FORMAT", "asciinema").to_ascii_lowercase().as_str() {
+            "msgpack" | "messagepack" => RecordFormat::MessagePack,
+            _ => RecordFormat::Asciinema,
+        }
+    }
+
+    /// File extension used for this format
+    fn extension(&self) -> &'static str {
+        match self {
+            RecordFormat::Asciinema => "cast",
+            RecordFormat::MessagePack => "msgpack",
+        }
+    }
 }
 
+/// Captures every byte flowing to and from a single channel so that an
+/// authenticated key's activity can be replayed for auditing.
+struct SessionRecorder {
+    writer: BufWriter<File>,
+    format: RecordFormat,
+    start: Instant,
+}
+
+impl SessionRecorder {
+    /// Creates a recorder for `key_id`/`connection_id`, writing an asciinema
+    /// header immediately when that format is selected
+    async fn create(
+        key_id: &str,
+        connection_id: usize,
+        channel: ChannelId,
+        width: u32,
+        height: u32,
+    ) -> std::io::Result<Self> {
+        let dir = get_env("RECORD_DIR", "recordings");
+        tokio::fs::create_dir_all(&dir).await?;
+        let format = RecordFormat::from_env();
+        let path = format!(
+            "{}/{}-{}-{}.{}",
+            dir,
+            key_id,
+            connection_id,
+            channel,
+            format.extension()
+        );
+        let mut writer = BufWriter::new(File::create(&path).await?);
+
+        if let RecordFormat::Asciinema = format {
+            let header = format!("{{\"version\":2,\"width\":{},\"height\":{}}}\n", width, height);
+            writer.write_all(header.as_bytes()).await?;
+            writer.flush().await?;
+        }
+
+        Ok(Self {
+            writer,
+            format,
+            start: Instant::now(),
+        })
+    }
+
+    /// Appends a chunk and flushes it so the recording stays current
+    async fn record(&mut self, stream: Stream, data: &[u8]) -> std::io::Result<()> {
+        let elapsed = self.start.elapsed();
+        match self.format {
+            RecordFormat::Asciinema => {
+                let code = match stream {
+                    Stream::Stdin => "i",
+                    Stream::Stdout => "o",
+                };
+                let payload = serde_json::to_string(&String::from_utf8_lossy(data))
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+                let line = format!("[{}, \"{}\", {}]\n", elapsed.as_secs_f64(), code, payload);
+                self.writer.write_all(line.as_bytes()).await?;
+            }
+            RecordFormat::MessagePack => {
+                let item = RecordItem {
+                    time_offset_ms: elapsed.as_millis() as u64,
+                    stream,
+                    data: data.to_vec(),
+                };
+                let encoded = rmp_serde::to_vec(&item)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+                self.writer.write_all(&encoded).await?;
+            }
+        }
+        self.writer.flush().await
+    }
+}
+
+/// Convenience alias for a recorder shared across the inbound and outbound tasks
+type SharedRecorder = Arc<Mutex<SessionRecorder>>;
+
 #[tokio::main]
 async fn main() {
     // Load environment variables from `.env` file if available
@@ -40,11 +237,18 @@ async fn main() {
         .init();
 
     // Configure SSH server settings, such as timeouts and authentication handling
+    let server_config = ServerConfig::from_env();
     let config = russh::server::Config {
         inactivity_timeout: Some(std::time::Duration::from_secs(3600)),
         auth_rejection_time: std::time::Duration::from_secs(3),
         auth_rejection_time_initial: Some(std::time::Duration::from_secs(0)),
-        keys: vec![russh_keys::key::KeyPair::generate_ed25519().unwrap()],
+        // Explain up front why an otherwise valid key may be turned away, so a
+        // rate-limited client sees a clear reason instead of a bare failure.
+        auth_banner: Some(RATE_LIMIT_BANNER),
+        // Stable host identities loaded from disk, so clients don't see a
+        // changed key on every restart.
+        keys: load_or_generate_host_keys(&server_config.host_key_dir),
+        preferred: server_config.preferred(),
         ..Default::default()
     };
     let config = Arc::new(config);
@@ -52,8 +256,14 @@ async fn main() {
     // Initialize and run the server on a specified address and port
     let mut server_instance = Server {
         clients: Arc::new(Mutex::new(HashMap::new())),
+        ptys: Arc::new(Mutex::new(HashMap::new())),
+        shells: Arc::new(Mutex::new(HashMap::new())),
+        recorders: Arc::new(Mutex::new(HashMap::new())),
+        forwards: Arc::new(Mutex::new(HashMap::new())),
         id: 0,
         connect_username: String::new(),
+        connect_key_id: String::new(),
+        connect_scopes: Vec::new(),
     };
     server_instance
         .run_on_address(config, ("0.0.0.0", 2222))
@@ -65,8 +275,28 @@ async fn main() {
 #[derive(Clone)]
 struct Server {
     clients: Arc<Mutex<HashMap<(usize, ChannelId), russh::server::Handle>>>,
+    /// Terminal parameters recorded from `pty_request`, keyed by channel
+    ptys: Arc<Mutex<HashMap<(usize, ChannelId), PtyRequest>>>,
+    /// Stdin handles of the shells spawned for interactive channels
+    shells: Arc<Mutex<HashMap<(usize, ChannelId), ChildStdin>>>,
+    /// Per-channel recorders capturing inbound and outbound bytes
+    recorders: Arc<Mutex<HashMap<(usize, ChannelId), SharedRecorder>>>,
+    /// Active remote forwardings, keyed by the bound `address:port`, so that a
+    /// `cancel_tcpip_forward` can tear the matching listener down
+    forwards: Arc<Mutex<HashMap<(String, u32), JoinHandle<()>>>>,
     id: usize,
     connect_username: String,
+    /// Unkey key id of the verified key used to authenticate this connection
+    connect_key_id: String,
+    /// Permission scopes granted to the verified key, consulted by feature gates
+    connect_scopes: Vec<String>,
+}
+
+impl Server {
+    /// Returns `true` when the verified key was granted `scope`
+    fn has_scope(&self, scope: &str) -> bool {
+        self.connect_scopes.iter().any(|s| s == scope)
+    }
 }
 
 impl Server {
@@ -75,10 +305,20 @@ impl Server {
         let mut clients = self.clients.lock().await;
         for ((client_id, channel), client_handle) in clients.iter_mut() {
             if !exclude_self || *client_id != self.id {
+                self.record((*client_id, *channel), Stream::Stdout, &data).await;
                 let _ = client_handle.data(*channel, data.clone()).await;
             }
         }
     }
+
+    /// Looks up the recorder for a channel and appends a chunk, ignoring write
+    /// errors so recording never takes down a live session
+    async fn record(&self, key: (usize, ChannelId), stream: Stream, data: &[u8]) {
+        let recorder = self.recorders.lock().await.get(&key).cloned();
+        if let Some(recorder) = recorder {
+            let _ = recorder.lock().await.record(stream, data).await;
+        }
+    }
 }
 
 /// Implementation of the SSH `Server` trait to handle new clients and session errors
@@ -112,11 +352,37 @@ impl server::Handler for Server {
             clients.insert((self.id, channel.id()), session.handle());
         }
 
+        // Begin recording this channel keyed by the verified key id and connection
+        if let Ok(recorder) =
+            SessionRecorder::create(&self.connect_key_id, self.id, channel.id(), 80, 24).await
+        {
+            self.recorders
+                .lock()
+                .await
+                .insert((self.id, channel.id()), Arc::new(Mutex::new(recorder)));
+        }
+
         let message = format!("{} connected to the server.\r\n", self.connect_username);
         self.post(CryptoVec::from(message), false).await;
         Ok(true)
     }
 
+    /// Releases the per-channel state once the client closes the channel, so a
+    /// long-running server doesn't leak recorder file descriptors, pty entries
+    /// or client handles for the lifetime of the process.
+    async fn channel_close(
+        &mut self,
+        channel: ChannelId,
+        _session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let key = (self.id, channel);
+        self.recorders.lock().await.remove(&key);
+        self.ptys.lock().await.remove(&key);
+        self.shells.lock().await.remove(&key);
+        self.clients.lock().await.remove(&key);
+        Ok(())
+    }
+
     /// Authenticates a client using a password by verifying the key with the Unkey service
     async fn auth_password(
         &mut self,
@@ -129,31 +395,302 @@ impl server::Handler for Server {
             Some(key) if !key.valid => Ok(server::Auth::Reject {
                 proceed_with_methods: Some(MethodSet::PASSWORD),
             }),
-            _ => {
+            // A verified but rate-limited key is turned away with no further
+            // methods offered; the client has already been shown the
+            // `RATE_LIMIT_BANNER` explaining why the refusal happened.
+            Some(key) if key.rate_limited() => {
+                info!(
+                    "rejecting {}: Unkey rate limit exhausted (resets at {})",
+                    user,
+                    key.ratelimit.as_ref().map(|r| r.reset).unwrap_or_default()
+                );
+                Ok(server::Auth::Reject {
+                    proceed_with_methods: None,
+                })
+            }
+            key => {
+                if let Some(key) = key {
+                    self.connect_key_id = key.key_id;
+                    self.connect_scopes = key.scopes();
+                }
                 self.connect_username = user.to_string();
                 Ok(server::Auth::Accept)
             }
         }
     }
 
-    /// Rejects authentication by public key, prompting clients to use passwords
+    /// Query phase: accept the offered key (so the client goes on to sign) when
+    /// its fingerprint is listed in the Unkey key's `authorized_fingerprints`.
+    /// The SSH username carries the Unkey key whose metadata grants access.
+    async fn auth_publickey_offered(
+        &mut self,
+        user: &str,
+        public_key: &key::PublicKey,
+    ) -> Result<server::Auth, Self::Error> {
+        match verify_key(user).await {
+            Some(key)
+                if key.valid
+                    && key
+                        .authorized_fingerprints()
+                        .contains(&public_key.fingerprint()) =>
+            {
+                Ok(server::Auth::Accept)
+            }
+            _ => Ok(server::Auth::Reject {
+                proceed_with_methods: Some(MethodSet::PASSWORD),
+            }),
+        }
+    }
+
+    /// Verify phase: russh has already checked the client's signature, so a
+    /// still-authorized fingerprint completes public-key authentication.
     async fn auth_publickey(
         &mut self,
-        _: &str,
-        _: &key::PublicKey,
+        user: &str,
+        public_key: &key::PublicKey,
     ) -> Result<server::Auth, Self::Error> {
-        Ok(server::Auth::Reject {
-            proceed_with_methods: Some(MethodSet::PASSWORD),
-        })
+        match verify_key(user).await {
+            Some(key)
+                if key.valid
+                    && !key.rate_limited()
+                    && key
+                        .authorized_fingerprints()
+                        .contains(&public_key.fingerprint()) =>
+            {
+                self.connect_scopes = key.scopes();
+                self.connect_key_id = key.key_id;
+                self.connect_username = user.to_string();
+                Ok(server::Auth::Accept)
+            }
+            _ => Ok(server::Auth::Reject {
+                proceed_with_methods: Some(MethodSet::PASSWORD),
+            }),
+        }
+    }
+
+    /// Records the terminal the client asked for so a later `shell_request`
+    /// can launch a child with a matching environment
+    async fn pty_request(
+        &mut self,
+        channel: ChannelId,
+        term: &str,
+        col_width: u32,
+        row_height: u32,
+        _pix_width: u32,
+        _pix_height: u32,
+        _modes: &[(Pty, u32)],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let pty = PtyRequest {
+            term: term.to_string(),
+            col_width,
+            row_height,
+        };
+        self.ptys.lock().await.insert((self.id, channel), pty);
+        session.channel_success(channel);
+        Ok(())
     }
 
-    /// Handles data received from the client, sending it to all other clients
+    /// Spawns an interactive shell for the channel and wires its output back to
+    /// the client, forwarding subsequent `data` into the child's stdin
+    async fn shell_request(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        // An interactive shell is at least as powerful as a one-shot command,
+        // so it is gated behind the same scopes as `exec_request` to keep the
+        // privilege model consistent.
+        if !self.has_scope("shell") && !self.has_scope("exec") {
+            session.data(
+                channel,
+                CryptoVec::from(
+                    "permission denied: key lacks the `shell` scope\r\n".to_string(),
+                ),
+            );
+            let _ = session.exit_status_request(channel, 1);
+            session.close(channel);
+            return Ok(());
+        }
+
+        let pty = self.ptys.lock().await.get(&(self.id, channel)).cloned();
+
+        let mut command = Command::new(default_shell());
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if let Some(pty) = pty {
+            command
+                .env("TERM", pty.term)
+                .env("COLUMNS", pty.col_width.to_string())
+                .env("LINES", pty.row_height.to_string());
+        }
+
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(err) => {
+                let message = format!("failed to start shell: {}\r\n", err);
+                session.data(channel, CryptoVec::from(message));
+                session.close(channel);
+                return Ok(());
+            }
+        };
+
+        if let Some(stdin) = child.stdin.take() {
+            self.shells.lock().await.insert((self.id, channel), stdin);
+        }
+
+        let handle = session.handle();
+        let stdout = child.stdout.take();
+        let stderr = child.stderr.take();
+        let shells = self.shells.clone();
+        let key = (self.id, channel);
+        let recorder = self.recorders.lock().await.get(&key).cloned();
+
+        tokio::spawn(async move {
+            if let (Some(mut stdout), Some(mut stderr)) = (stdout, stderr) {
+                let mut out_buf = [0u8; 4096];
+                let mut err_buf = [0u8; 4096];
+                // Track each stream independently and stop polling it once it
+                // reaches EOF; otherwise a closed fd keeps returning `Ok(0)` and
+                // `select!` would spin on it until the other stream closes.
+                let mut stdout_open = true;
+                let mut stderr_open = true;
+                while stdout_open || stderr_open {
+                    tokio::select! {
+                        n = stdout.read(&mut out_buf), if stdout_open => match n {
+                            Ok(0) | Err(_) => stdout_open = false,
+                            Ok(n) => {
+                                record_outbound(&recorder, &out_buf[..n]).await;
+                                let _ = handle.data(channel, CryptoVec::from_slice(&out_buf[..n])).await;
+                            }
+                        },
+                        n = stderr.read(&mut err_buf), if stderr_open => match n {
+                            Ok(0) | Err(_) => stderr_open = false,
+                            Ok(n) => {
+                                record_outbound(&recorder, &err_buf[..n]).await;
+                                let _ = handle.data(channel, CryptoVec::from_slice(&err_buf[..n])).await;
+                            }
+                        },
+                    }
+                }
+            }
+
+            let status = child.wait().await.ok();
+            let code = status.and_then(|s| s.code()).unwrap_or(0) as u32;
+            shells.lock().await.remove(&key);
+            let _ = handle.exit_status_request(channel, code).await;
+            let _ = handle.eof(channel).await;
+            let _ = handle.close(channel).await;
+        });
+
+        session.channel_success(channel);
+        Ok(())
+    }
+
+    /// Runs a one-shot command and reports its exit status back on the channel
+    async fn exec_request(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        // Running commands requires the `exec` scope on the verified key
+        if !self.has_scope("exec") {
+            session.data(
+                channel,
+                CryptoVec::from("permission denied: key lacks the `exec` scope\r\n".to_string()),
+            );
+            let _ = session.exit_status_request(channel, 1);
+            session.close(channel);
+            return Ok(());
+        }
+
+        let command_line = String::from_utf8_lossy(data).to_string();
+        let handle = session.handle();
+        let recorder = self.recorders.lock().await.get(&(self.id, channel)).cloned();
+
+        tokio::spawn(async move {
+            let output = Command::new(default_shell())
+                .arg("-c")
+                .arg(&command_line)
+                .output()
+                .await;
+
+            let code = match output {
+                Ok(output) => {
+                    if !output.stdout.is_empty() {
+                        record_outbound(&recorder, &output.stdout).await;
+                        let _ = handle.data(channel, CryptoVec::from(output.stdout)).await;
+                    }
+                    if !output.stderr.is_empty() {
+                        record_outbound(&recorder, &output.stderr).await;
+                        let _ = handle
+                            .extended_data(channel, 1, CryptoVec::from(output.stderr))
+                            .await;
+                    }
+                    output.status.code().unwrap_or(0) as u32
+                }
+                Err(err) => {
+                    let message = format!("failed to run command: {}\r\n", err);
+                    let _ = handle.data(channel, CryptoVec::from(message)).await;
+                    127
+                }
+            };
+
+            let _ = handle.exit_status_request(channel, code).await;
+            let _ = handle.eof(channel).await;
+            let _ = handle.close(channel).await;
+        });
+
+        session.channel_success(channel);
+        Ok(())
+    }
+
+    /// Updates the stored terminal size when the client resizes its window.
+    ///
+    /// Accepted scope: allocating a real PTY (via `openpty`/`portable-pty`) is
+    /// out of scope for this server — the shell is a best-effort, pipe-based
+    /// `sh` rather than a full interactive terminal, so it has no line editing,
+    /// no job-control prompt and receives no SIGWINCH. Consequently there is no
+    /// terminal device to apply `TIOCSWINSZ` to, and live resize is not
+    /// supported. The stored dimensions only take effect through the
+    /// `COLUMNS`/`LINES` environment of a shell started afterwards.
+    async fn window_change_request(
+        &mut self,
+        channel: ChannelId,
+        col_width: u32,
+        row_height: u32,
+        _pix_width: u32,
+        _pix_height: u32,
+        _session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        if let Some(pty) = self.ptys.lock().await.get_mut(&(self.id, channel)) {
+            pty.col_width = col_width;
+            pty.row_height = row_height;
+        }
+        Ok(())
+    }
+
+    /// Handles data received from the client: forwards it into the channel's
+    /// shell when one is running, otherwise broadcasts it as chat
     async fn data(
         &mut self,
-        _: ChannelId,
+        channel: ChannelId,
         data: &[u8],
         _: &mut Session,
     ) -> Result<(), Self::Error> {
+        // Record inbound bytes before they are dispatched
+        self.record((self.id, channel), Stream::Stdin, data).await;
+
+        // When an interactive shell is attached, the bytes are terminal input
+        if let Some(stdin) = self.shells.lock().await.get_mut(&(self.id, channel)) {
+            let _ = stdin.write_all(data).await;
+            let _ = stdin.flush().await;
+            return Ok(());
+        }
+
         // Sending Ctrl+C ends the session and disconnects the client
         if data == [3] {
             let message = format!(
@@ -174,31 +711,240 @@ impl server::Handler for Server {
         Ok(())
     }
 
-    /// Sets up port forwarding to allow clients to access services through this server
+    /// Binds a local listener and relays each inbound connection back to the
+    /// client over a `forwarded-tcpip` channel (remote, `-R`, forwarding)
     async fn tcpip_forward(
         &mut self,
         address: &str,
         port: &mut u32,
         session: &mut Session,
     ) -> Result<bool, Self::Error> {
+        // Port forwarding requires the `forward` scope on the verified key
+        if !self.has_scope("forward") {
+            info!("denying tcpip_forward: key lacks the `forward` scope");
+            return Ok(false);
+        }
+
+        let listener = match TcpListener::bind((address, *port as u16)).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                info!("failed to bind {}:{} for forwarding: {}", address, port, err);
+                return Ok(false);
+            }
+        };
+
+        // A port of 0 asks the server to allocate one; report it back.
+        if let Ok(local) = listener.local_addr() {
+            *port = local.port() as u32;
+        }
+
         let handle = session.handle();
-        let address = address.to_string();
-        let port = *port;
+        let bind_address = address.to_string();
+        let bind_port = *port;
+
+        let task = tokio::spawn(async move {
+            loop {
+                let (mut inbound, peer) = match listener.accept().await {
+                    Ok(pair) => pair,
+                    Err(_) => break,
+                };
+
+                let channel = match handle
+                    .channel_open_forwarded_tcpip(
+                        bind_address.clone(),
+                        bind_port,
+                        peer.ip().to_string(),
+                        peer.port() as u32,
+                    )
+                    .await
+                {
+                    Ok(channel) => channel,
+                    Err(_) => continue,
+                };
+
+                tokio::spawn(async move {
+                    let mut stream = channel.into_stream();
+                    let _ = tokio::io::copy_bidirectional(&mut inbound, &mut stream).await;
+                });
+            }
+        });
+
+        self.forwards
+            .lock()
+            .await
+            .insert((bind_address, bind_port), task);
+
+        Ok(true)
+    }
+
+    /// Drops the listener backing a previously requested remote forwarding
+    async fn cancel_tcpip_forward(
+        &mut self,
+        address: &str,
+        port: u32,
+        _session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        match self.forwards.lock().await.remove(&(address.to_string(), port)) {
+            Some(task) => {
+                task.abort();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Connects to a service behind the server and relays the client's channel
+    /// to it (local, `-L`, forwarding)
+    async fn channel_open_direct_tcpip(
+        &mut self,
+        channel: Channel<Msg>,
+        host_to_connect: &str,
+        port_to_connect: u32,
+        _originator_address: &str,
+        _originator_port: u32,
+        _session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        let target = match TcpStream::connect((host_to_connect, port_to_connect as u16)).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                info!(
+                    "direct-tcpip to {}:{} failed: {}",
+                    host_to_connect, port_to_connect, err
+                );
+                return Ok(false);
+            }
+        };
 
-        // Spawns a background task for port forwarding
         tokio::spawn(async move {
-            let channel = handle
-                .channel_open_forwarded_tcpip(address, port, "1.2.3.4", 1234)
-                .await
-                .unwrap();
-            let _ = channel.data(&b"Hello from a forwarded port"[..]).await;
-            let _ = channel.eof().await;
+            let mut target = target;
+            let mut stream = channel.into_stream();
+            let _ = tokio::io::copy_bidirectional(&mut stream, &mut target).await;
         });
 
         Ok(true)
     }
 }
 
+/// Records outbound bytes through a [`SharedRecorder`] if one is attached,
+/// swallowing any write error so recording never disrupts the stream
+async fn record_outbound(recorder: &Option<SharedRecorder>, data: &[u8]) {
+    if let Some(recorder) = recorder {
+        let _ = recorder.lock().await.record(Stream::Stdout, data).await;
+    }
+}
+
+/// Cipher ordering that prefers the AEAD `chacha20-poly1305` suite while
+/// keeping the remaining defaults as fallbacks
+static CHACHA20_FIRST_CIPHERS: &[cipher::Name] = &[
+    cipher::CHACHA20_POLY1305,
+    cipher::AES_256_GCM,
+    cipher::AES_256_CTR,
+    cipher::AES_192_CTR,
+    cipher::AES_128_CTR,
+];
+
+/// MAC ordering that prefers the encrypt-then-MAC variants over their
+/// plain counterparts, keeping the remaining defaults as fallbacks
+static ETM_FIRST_MACS: &[mac::Name] = &[
+    mac::HMAC_SHA512_ETM,
+    mac::HMAC_SHA256_ETM,
+    mac::HMAC_SHA512,
+    mac::HMAC_SHA256,
+];
+
+/// Runtime-tunable server settings sourced from the environment, giving
+/// deployments stable host identities and control over negotiated algorithms.
+struct ServerConfig {
+    /// Directory host keys are loaded from (and generated into on first run)
+    host_key_dir: String,
+    /// Whether to advertise the AEAD `chacha20-poly1305` suite first
+    prefer_chacha20: bool,
+    /// Whether to advertise the encrypt-then-MAC algorithms first
+    prefer_etm_macs: bool,
+}
+
+impl ServerConfig {
+    /// Reads the settings from the environment, falling back to sensible defaults
+    fn from_env() -> Self {
+        ServerConfig {
+            host_key_dir: get_env("HOST_KEY_DIR", "keys"),
+            prefer_chacha20: get_env("SSH_PREFER_CHACHA20", "true") != "false",
+            prefer_etm_macs: get_env("SSH_PREFER_ETM_MACS", "true") != "false",
+        }
+    }
+
+    /// Builds the preferred-algorithm set for the negotiated transport
+    fn preferred(&self) -> Preferred {
+        let mut preferred = Preferred::DEFAULT;
+        if self.prefer_chacha20 {
+            preferred.cipher = CHACHA20_FIRST_CIPHERS;
+        }
+        if self.prefer_etm_macs {
+            preferred.mac = ETM_FIRST_MACS;
+        }
+        preferred
+    }
+}
+
+/// Loads the server's host keys from `dir`, generating and persisting both an
+/// ed25519 and an RSA key on first run. An ECDSA key is picked up when the
+/// operator drops its PEM next to them; russh_keys offers no ECDSA key
+/// generation, so it is load-only rather than created here.
+fn load_or_generate_host_keys(dir: &str) -> Vec<russh_keys::key::KeyPair> {
+    let _ = std::fs::create_dir_all(dir);
+    let mut keys = Vec::new();
+
+    let ed25519_path = format!("{}/ssh_host_ed25519_key", dir);
+    if let Some(key) =
+        load_or_create_host_key(&ed25519_path, russh_keys::key::KeyPair::generate_ed25519)
+    {
+        keys.push(key);
+    }
+
+    let rsa_path = format!("{}/ssh_host_rsa_key", dir);
+    if let Some(key) = load_or_create_host_key(&rsa_path, || {
+        russh_keys::key::KeyPair::generate_rsa(4096, russh_keys::key::SignatureHash::SHA2_512)
+    }) {
+        keys.push(key);
+    }
+
+    let ecdsa_path = format!("{}/ssh_host_ecdsa_key", dir);
+    if let Ok(key) = russh_keys::load_secret_key(&ecdsa_path, None) {
+        keys.push(key);
+    }
+
+    keys
+}
+
+/// Loads a host key from `path`, or generates one with `generate` and persists
+/// it as a PKCS#8 PEM when the file does not yet exist.
+fn load_or_create_host_key(
+    path: &str,
+    generate: impl Fn() -> Option<russh_keys::key::KeyPair>,
+) -> Option<russh_keys::key::KeyPair> {
+    if let Ok(key) = russh_keys::load_secret_key(path, None) {
+        return Some(key);
+    }
+
+    let key = generate()?;
+    match std::fs::File::create(path) {
+        Ok(file) => {
+            let mut writer = std::io::BufWriter::new(file);
+            if let Err(err) = russh_keys::encode_pkcs8_pem(&key, &mut writer) {
+                info!("failed to persist host key {}: {}", path, err);
+            }
+        }
+        Err(err) => info!("failed to create host key {}: {}", path, err),
+    }
+    Some(key)
+}
+
+/// Returns the shell to spawn for interactive and one-shot commands, honouring
+/// the `SHELL` environment variable and falling back to `/bin/sh`
+fn default_shell() -> String {
+    env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string())
+}
+
 /// Function to verify an API key using the Unkey service, returning `KeyVerifyData`
 async fn verify_key(key: &str) -> Option<KeyVerifyData> {
     let unkey_client = UnkeyClient::new(UNKEY_ROOT_KEY.as_str());
@@ -208,5 +954,14 @@ async fn verify_key(key: &str) -> Option<KeyVerifyData> {
         .verify_key(req)
         .await
         .ok()
-        .map(|res| KeyVerifyData { valid: res.valid })
+        .map(|res| KeyVerifyData {
+            valid: res.valid,
+            key_id: res.key_id.unwrap_or_default(),
+            meta: res.meta,
+            ratelimit: res.ratelimit.map(|r| RateLimitInfo {
+                limit: r.limit,
+                remaining: r.remaining,
+                reset: r.reset,
+            }),
+        })
 }